@@ -1,39 +1,43 @@
-use proc_macro;
-use proc_macro2;
+use proc_macro2::Span;
 
 use quote::{quote, format_ident};
-use syn;
+use sha2::{Digest, Sha256};
+use syn::{
+  self,
+  punctuated::Punctuated,
+  spanned::Spanned,
+  token::Comma,
+  ImplItem, ItemImpl, Lit, MetaNameValue,
+};
 
-struct ParseError;
-
-#[derive(Debug)]
-enum DispatchType {
-    MethodNum(String),
-    AbiSelector(String)
-}
-
-#[derive(Default, Debug)]
 struct FvmActorMacroAttribute {
   state: String,
+  state_span: Span,
   dispatch_type: String,
-  invoke: bool
+  invoke: bool,
 }
 
-#[derive(Debug)]
-struct ExportAttribute {
-  fn_name: String,
-  binding: String
-}
-
-impl ExportAttribute {
-  fn new(fn_name: String, binding: String) -> Self {
-    ExportAttribute {
-      fn_name,
-      binding
+impl Default for FvmActorMacroAttribute {
+  fn default() -> Self {
+    FvmActorMacroAttribute {
+      state: String::new(),
+      state_span: Span::call_site(),
+      dispatch_type: String::new(),
+      invoke: false,
     }
   }
 }
 
+struct ExportAttribute {
+  fn_name: syn::Ident,
+  binding: Option<Lit>,
+  guards: Vec<syn::Ident>,
+  sig: syn::Signature,
+  is_constructor: bool,
+  is_mutating: bool,
+  span: Span,
+}
+
 #[proc_macro_derive(StateObject)]
 pub fn fvm_state_macro_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Construct a representation of Rust code as a syntax tree
@@ -54,7 +58,7 @@ fn impl_fvm_state_macro(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
                   Ok(root) => root,
                   Err(err) => abort!(USR_ILLEGAL_STATE, "failed to get root: {:?}", err),
               };
-      
+
               // Load the actor state from the state tree.
               match Blockstore.get_cbor::<Self>(&root) {
                   Ok(Some(state)) => state,
@@ -76,7 +80,7 @@ fn impl_fvm_state_macro(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
                 abort!(USR_ILLEGAL_STATE, "failed to set root ciid: {:}", err);
             }
             cid
-          }  
+          }
         }
     };
     gen.into()
@@ -84,28 +88,86 @@ fn impl_fvm_state_macro(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
 
 #[proc_macro_attribute]
 pub fn fvm_actor(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-  let input = proc_macro2::TokenStream::from(item);
-  let clone = input.clone();
- 
-  check_impl(&clone);
+  let mut errors: Vec<syn::Error> = vec![];
+
+  // Parse the attribute arguments as a comma separated list of `name = value`
+  // pairs, and the annotated item as an inherent impl block. Parse failures are
+  // surfaced as normal Rust diagnostics rather than panics.
+  let args = match syn::parse::<AttrArgs>(attr) {
+    Ok(args) => args.0,
+    Err(err) => {
+      errors.push(err);
+      Punctuated::new()
+    }
+  };
+
+  let item_impl = match syn::parse::<ItemImpl>(item.clone()) {
+    Ok(item_impl) => item_impl,
+    Err(err) => {
+      // We cannot reason about the body without a parsed impl, so emit the
+      // error alongside the original tokens and stop here.
+      let compile_errors = to_compile_errors(vec![err]);
+      let original = proc_macro2::TokenStream::from(item);
+      return quote! { #original #compile_errors }.into();
+    }
+  };
+
+  check_impl(&item_impl, &mut errors);
 
-  let macro_attributes = parse_attributes(attr.to_string());
-  let (name, fns) = meta(&clone);
+  let macro_attributes = parse_attributes(&args, &mut errors);
+  let exports = collect_exports(&item_impl, &mut errors);
 
-  impl_fvm_actor(macro_attributes, name, fns, input)
+  impl_fvm_actor(macro_attributes, &item_impl, exports, errors).into()
 }
 
-fn impl_fvm_actor(macro_attributes: FvmActorMacroAttribute, name: proc_macro2::TokenTree, fns: Vec<ExportAttribute>, original_stream: proc_macro2::TokenStream) -> proc_macro::TokenStream {
+/// Newtype so we can `syn::parse` the attribute list with `parse_terminated`.
+struct AttrArgs(Punctuated<MetaNameValue, Comma>);
+
+impl syn::parse::Parse for AttrArgs {
+  fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+    Ok(AttrArgs(Punctuated::parse_terminated(input)?))
+  }
+}
+
+fn impl_fvm_actor(macro_attributes: FvmActorMacroAttribute, item_impl: &ItemImpl, fns: Vec<ExportAttribute>, mut errors: Vec<syn::Error>) -> proc_macro2::TokenStream {
+
+  let name = &item_impl.self_ty;
+
+  let state_name = if macro_attributes.state.is_empty() { "State".to_string() } else { macro_attributes.state.clone() };
+  let state_class = syn::Ident::new(&state_name, macro_attributes.state_span);
+
+  // At most one method may be marked `#[constructor]`; it becomes method 1.
+  let constructors: Vec<&ExportAttribute> = fns.iter().filter(|f| f.is_constructor).collect();
+  if constructors.len() > 1 {
+    for ctor in &constructors[1..] {
+      errors.push(syn::Error::new(ctor.span, "only one method may be marked #[constructor]"));
+    }
+  }
+  let constructor = constructors.first().copied();
+
+  // Guards run against the loaded state, which does not exist yet on the
+  // construction path, so `#[access_control]` on a `#[constructor]` has nowhere
+  // to run. Reject it rather than silently dropping the authorization check.
+  if let Some(ctor) = constructor {
+    if !ctor.guards.is_empty() {
+      errors.push(syn::Error::new(ctor.span, "#[access_control] is not supported on #[constructor] methods"));
+    }
+  }
 
   let arms = fns
     .iter()
-    .enumerate()
-    .map(|(_i, x)| match_arm(x, &name, &macro_attributes.dispatch_type)).collect::<Vec<_>>();
+    .filter(|f| !f.is_constructor)
+    .map(|x| match_arm(x, name, &macro_attributes.dispatch_type, &mut errors))
+    .collect::<Vec<_>>();
+
+  // Any `#[mutates]` method needs the loaded state bound mutably so it can take
+  // `&mut state`.
+  let any_mutates = fns.iter().any(|f| !f.is_constructor && f.is_mutating);
+  let state_binding = if any_mutates { quote! { mut state } } else { quote! { state } };
 
-  let state_class = format_ident!("{}", macro_attributes.state);
   let mut invoke_block = quote! {};
 
-  if macro_attributes.invoke != false {
+  if macro_attributes.invoke {
     invoke_block = quote! {
       #[no_mangle]
       pub fn invoke(id: u32) -> u32 {
@@ -114,30 +176,96 @@ fn impl_fvm_actor(macro_attributes: FvmActorMacroAttribute, name: proc_macro2::T
     };
   }
 
+  // Construction path: with an explicit `#[constructor]` we route method 1 to
+  // it and persist the returned state; otherwise method 1 falls back to
+  // `Default::default()`, which requires the state type to implement `Default`.
+  let (load_body, dispatch_body) = match constructor {
+    Some(ctor) => {
+      let ctor_fn = &ctor.fn_name;
+      let (decode, call) = decode_and_call_impl(&ctor.sig, name, ctor_fn, quote! {});
+      (
+        quote! { <#state_class>::load() },
+        quote! {
+          let method = sdk::message::method_number();
+          let ret: Option<RawBytes> = match method {
+            1 => {
+              #decode
+              let state = #call;
+              StateObject::save(&state);
+              None
+            },
+            _ => {
+              let #state_binding: #state_class = <#name>::load();
+              match method {
+                #(#arms)*
+                _ => abort!(USR_UNHANDLED_MESSAGE, "unrecognized method"),
+              }
+            }
+          };
+        },
+      )
+    }
+    None => (
+      quote! {
+        match sdk::message::method_number() {
+          1 => <#state_class>::default(),
+          _ => <#state_class>::load()
+        }
+      },
+      quote! {
+        let #state_binding: #state_class = <#name>::load();
+
+        let ret: Option<RawBytes> = match sdk::message::method_number() {
+          #(#arms)*
+          _ => abort!(USR_UNHANDLED_MESSAGE, "unrecognized method"),
+        };
+      },
+    ),
+  };
+
+  // Without a constructor the fallback leans on `Default`, so assert the state
+  // type actually implements it. The probe is anchored on the declared state
+  // type and carries a custom message spelling out the constructor requirement,
+  // so a missing `Default` points the author at the real fix instead of the
+  // bare "trait bound not satisfied".
+  let default_assertion = if constructor.is_none() {
+    let assert_trait = syn::Ident::new("_FvmConstructorOrDefault", macro_attributes.state_span);
+    let assert_fn = syn::Ident::new("_assert_state_default", macro_attributes.state_span);
+    quote! {
+      const _: fn() = || {
+        #[diagnostic::on_unimplemented(
+          message = "no `#[constructor]` method found and `{Self}` does not implement `Default`",
+          note = "mark a method with `#[constructor]`, or implement `Default` for the state type so method 1 can fall back to `Default::default()`",
+        )]
+        trait #assert_trait {}
+        impl<T: ::core::default::Default> #assert_trait for T {}
+        fn #assert_fn<T: #assert_trait>() {}
+        let _ = #assert_fn::<#state_class>;
+      };
+    }
+  } else {
+    quote! {}
+  };
+
+  let compile_errors = to_compile_errors(errors);
+
   let gen = quote!{
-    #original_stream
+    #item_impl
 
-    pub trait Actor { 
-      fn dispatch(id: u32) -> u32; 
+    pub trait Actor {
+      fn dispatch(id: u32) -> u32;
       fn load() -> #state_class;
     }
 
     impl Actor for #name {
       fn load() -> #state_class {
-        match sdk::message::method_number() {
-          1 => <#state_class>::default(),
-          _ => <#state_class>::load()
-        }
+        #load_body
       }
       fn dispatch(id: u32) -> u32 {
         let params = sdk::message::params_raw(id).unwrap().1;
         let params = RawBytes::new(params);
-        let state: #state_class = <#name>::load();
 
-        let ret: Option<RawBytes> = match sdk::message::method_number() {
-          #(#arms)*
-          _ => abort!(USR_UNHANDLED_MESSAGE, "unrecognized method"),
-        };
+        #dispatch_body
 
         match ret {
           None => NO_DATA_BLOCK_ID,
@@ -149,218 +277,346 @@ fn impl_fvm_actor(macro_attributes: FvmActorMacroAttribute, name: proc_macro2::T
       }
     }
 
+    #default_assertion
+
     #invoke_block
+
+    #compile_errors
   };
 
-  println!("{}", gen.to_string());
-  gen.into()
+  gen
 }
 
-fn match_arm(attr: &ExportAttribute, class_name: &proc_macro2::TokenTree, dispatch_type: &String) -> proc_macro2::TokenStream {
-  let fn_name = format_ident!("{}", attr.fn_name);
-  let lit = match dispatch_type.as_str() {
-    "method_num" => proc_macro2::Literal::usize_unsuffixed(attr.binding.parse().expect("binding must be a number")),
-    "abi_selector" => proc_macro2::Literal::string(&attr.binding),
-    _ => panic!("unsupported dispatch_type {}", dispatch_type)
+fn match_arm(attr: &ExportAttribute, class_name: &syn::Type, dispatch_type: &str, errors: &mut Vec<syn::Error>) -> proc_macro2::TokenStream {
+  let fn_name = &attr.fn_name;
+  let lit = match dispatch_type {
+    "method_num" => match &attr.binding {
+      Some(Lit::Int(i)) => match i.base10_parse::<u64>() {
+        Ok(n) => proc_macro2::Literal::u64_unsuffixed(n),
+        Err(err) => {
+          errors.push(err);
+          return quote! {};
+        }
+      },
+      _ => {
+        errors.push(syn::Error::new(attr.span, "binding must be an integer"));
+        return quote! {};
+      }
+    },
+    "abi_selector" => match &attr.binding {
+      Some(Lit::Str(s)) => proc_macro2::Literal::string(&s.value()),
+      _ => {
+        errors.push(syn::Error::new(attr.span, "binding must be a string literal"));
+        return quote! {};
+      }
+    },
+    // FRC-42: fall back to a method number derived from the method name when no
+    // explicit `binding` was provided.
+    "frc42" => match &attr.binding {
+      Some(Lit::Int(i)) => match i.base10_parse::<u64>() {
+        Ok(n) => proc_macro2::Literal::u64_unsuffixed(n),
+        Err(err) => {
+          errors.push(err);
+          return quote! {};
+        }
+      },
+      None => proc_macro2::Literal::u64_unsuffixed(frc42_method_number(&fn_name.to_string())),
+      _ => {
+        errors.push(syn::Error::new(attr.span, "binding must be an integer"));
+        return quote! {};
+      }
+    },
+    other => {
+      errors.push(syn::Error::new(Span::call_site(), format!("unsupported dispatch `{}`", other)));
+      return quote! {};
+    }
+  };
+
+  // Run each `#[access_control(..)]` guard against the loaded state and the raw
+  // params before dispatching; the first guard to return `Err(code)` aborts.
+  let guards = attr.guards.iter().map(|guard| {
+    let message = format!("access control check `{}` failed", guard);
+    quote! {
+      if let Err(code) = #guard(&state, &params) {
+        fvm_sdk::vm::abort(code.value(), Some(#message));
+      }
+    }
+  });
+
+  // `#[mutates]` methods receive `&mut state` and have their state persisted
+  // automatically once they return; others keep the by-value `state`.
+  let state_tail = if attr.is_mutating { quote! { &mut state } } else { quote! { state } };
+  let (decode, call) = decode_and_call_impl(&attr.sig, class_name, fn_name, state_tail);
+
+  let body = if attr.is_mutating {
+    quote! {
+      #decode
+      let ret = #call;
+      StateObject::save(&state);
+      ret
+    }
+  } else {
+    quote! {
+      #decode
+      #call
+    }
   };
 
-  quote! { #lit => <#class_name>::#fn_name(params, state), }
+  quote! {
+    #lit => {
+      #(#guards)*
+      #body
+    },
+  }
 }
 
-fn check_impl(t: &proc_macro2::TokenStream) {
-  let stream = t.clone();
-  let mut iter = stream.into_iter();
-
-  let first = iter.next().unwrap();
-  iter.next();
-  let third = iter.next().unwrap();
-
-  let first_ident = extract_identifier(&first);
-  let third_ident = extract_identifier(&third);
+/// Derive an FRC-42 method number from a method name, computed at compile time.
+/// The name is hashed as `"1|" + name`; the first four bytes of the SHA-256
+/// digest are read as a big-endian `u32`. Values below `2^24` fall in the
+/// reserved system range, so the payload is prefixed with another `"1|"` and
+/// re-hashed until the result escapes it.
+fn frc42_method_number(name: &str) -> u64 {
+  let mut payload = format!("1|{}", name);
+  loop {
+    let digest = Sha256::digest(payload.as_bytes());
+    let num = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    if num >= 1 << 24 {
+      return num as u64;
+    }
+    payload = format!("1|{}", payload);
+  }
+}
 
-  if first_ident != "impl" {
-    panic!("fvm_actor: this macro can only be used on struct impl blocks.");
+/// Build the param-decoding preamble and the call expression for an exported
+/// method. The declared argument types (everything before the trailing `state`
+/// parameter) are decoded positionally from the DAG-CBOR params, aborting with
+/// `USR_ILLEGAL_ARGUMENT` on a decode failure. A method declaring a single
+/// `RawBytes` param keeps receiving the raw bytes untouched. When `state_tail`
+/// is non-empty (e.g. `state` or `&mut state`) the trailing parameter is treated
+/// as the loaded state and appended to the call untouched; otherwise (e.g.
+/// constructors) every parameter is decoded.
+fn decode_and_call_impl(sig: &syn::Signature, class_name: &syn::Type, fn_name: &syn::Ident, state_tail: proc_macro2::TokenStream) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+  let types: Vec<&syn::Type> = sig
+    .inputs
+    .iter()
+    .filter_map(|arg| match arg {
+      syn::FnArg::Typed(pat) => Some(&*pat.ty),
+      syn::FnArg::Receiver(_) => None,
+    })
+    .collect();
+
+  let threads_state = !state_tail.is_empty();
+
+  // When threading state, the final parameter is the loaded `state`; everything
+  // before it is decoded from the params blob.
+  let data = if threads_state && !types.is_empty() { &types[..types.len() - 1] } else { &types[..] };
+  let tail = state_tail;
+  let tail_sep = if threads_state { quote! { , } } else { quote! {} };
+
+  // Preserve the historical raw form for `fn method(params: RawBytes, state)`.
+  if data.len() == 1 && is_raw_bytes(data[0]) {
+    return (quote! {}, quote! { <#class_name>::#fn_name(params #tail_sep #tail) });
   }
-  if third_ident == "for" {
-    panic!("fvm_actor: this macro does not support trait impl definitions, sorry!");
+
+  if data.is_empty() {
+    return (quote! {}, quote! { <#class_name>::#fn_name(#tail) });
   }
-}
 
-fn extract_identifier(tt: &proc_macro2::TokenTree) -> String {
-  let r = match tt {
-    proc_macro2::TokenTree::Ident(i) => Ok(i.to_string()),
-    _ => Err(ParseError)
+  let idents: Vec<syn::Ident> = (0..data.len()).map(|i| format_ident!("__arg{}", i)).collect();
+
+  let decode = if data.len() == 1 {
+    let ident = &idents[0];
+    let ty = data[0];
+    quote! {
+      let #ident: #ty = match params.deserialize() {
+        Ok(v) => v,
+        Err(err) => abort!(USR_ILLEGAL_ARGUMENT, "failed to decode params: {}", err),
+      };
+    }
+  } else {
+    quote! {
+      let (#(#idents),*): (#(#data),*) = match params.deserialize() {
+        Ok(v) => v,
+        Err(err) => abort!(USR_ILLEGAL_ARGUMENT, "failed to decode params: {}", err),
+      };
+    }
   };
 
-  r.unwrap_or_default()
+  (decode, quote! { <#class_name>::#fn_name(#(#idents,)* #tail) })
 }
 
-fn meta(ts: &proc_macro2::TokenStream) -> (proc_macro2::TokenTree, Vec<ExportAttribute>) {
-  let mut item_iter = ts.clone().into_iter();
-  let _impl = item_iter.next().unwrap();
-  let name = item_iter.next().unwrap();
-  let group = item_iter.next().unwrap();  
-  let exported_methods = methods(&group);
-  (name, exported_methods)
+/// Whether a declared parameter type is `RawBytes` (matched by the final path
+/// segment, so both `RawBytes` and `fvm_ipld_encoding::RawBytes` are honored).
+fn is_raw_bytes(ty: &syn::Type) -> bool {
+  matches!(ty, syn::Type::Path(p) if p.path.segments.last().map(|s| s.ident == "RawBytes").unwrap_or(false))
 }
 
-fn methods(tt: &proc_macro2::TokenTree) -> Vec<ExportAttribute> {
-  let mut previous: Option<proc_macro2::TokenTree> = None;
-  let mut current: Option<proc_macro2::TokenTree> = None;
-  let mut capture_next = false;
-  let mut next_binding: Option<String> = None;
-  
+/// Validate that the macro was applied to an inherent impl block, pointing any
+/// diagnostics at the offending `impl` keyword.
+fn check_impl(item_impl: &ItemImpl, errors: &mut Vec<syn::Error>) {
+  if item_impl.trait_.is_some() {
+    errors.push(syn::Error::new(item_impl.impl_token.span(), "fvm_actor can only be applied to inherent impls"));
+  }
+}
+
+/// Walk the impl items looking for `#[export(...)]` methods, recording the
+/// method name and its declared `binding`.
+fn collect_exports(item_impl: &ItemImpl, errors: &mut Vec<syn::Error>) -> Vec<ExportAttribute> {
   let mut exported: Vec<ExportAttribute> = vec![];
 
-  match tt {
-    proc_macro2::TokenTree::Group(g) => {
-      let gi = g.stream().into_iter();
-      for g in gi {
-        previous = current;
-        current = Some(g.clone());
-
-        match g {
-          proc_macro2::TokenTree::Group(g) => {
-            if previous.as_ref().unwrap().to_string() == "#" {
-              capture_next = true;
-              let inner = g.stream().into_iter();
-              for i in inner {
-                match i {
-                  proc_macro2::TokenTree::Group(g) => {
-                    next_binding = extract_binding(&parse_macro_args(g.stream().to_string()));
-                  },
-                  _ => ()
-                }
-              }
-            } else if capture_next {
-              capture_next = false;
-              match next_binding {
-                Some(binding) => {
-                  exported.push(ExportAttribute::new(previous.as_ref().unwrap().to_string(), binding));
-                  next_binding = None;
-                }
-                None => ()
-              }
+  for item in &item_impl.items {
+    if let ImplItem::Method(method) = item {
+      let export_attr = method.attrs.iter().find(|attr| attr.path.is_ident("export"));
+      let export_attr = match export_attr {
+        Some(attr) => attr,
+        None => continue,
+      };
+
+      let mut binding: Option<Lit> = None;
+      // A bare `#[export]` carries no arguments and leaves the binding unset.
+      let parsed = if export_attr.tokens.is_empty() {
+        Ok(Punctuated::new())
+      } else {
+        export_attr.parse_args_with(Punctuated::<MetaNameValue, Comma>::parse_terminated)
+      };
+      match parsed {
+        Ok(nvs) => {
+          for nv in nvs {
+            if nv.path.is_ident("binding") {
+              binding = Some(nv.lit);
             }
-          },
-          _ => {}
+          }
         }
+        Err(err) => errors.push(err),
       }
-    },
-    _ => ()
-  }
-
-  exported
-}
 
-fn extract_pub_fns(tt: &proc_macro2::TokenTree) -> Vec<String> {
-  let mut v: Vec<String> = vec![];
-  let mut current: String = "{}".to_owned();
-  let mut previous: String = "{}".to_owned();
-
-  match tt {
-    proc_macro2::TokenTree::Group(g) => {
-      let gi = g.stream().into_iter();
-      for g in gi {
-        if previous == "pub" && current == "fn" {
-          v.push(extract_identifier(&g));
+      // `#[access_control(guard_a, guard_b)]` lists guard functions to run, in
+      // order, before the method body.
+      let mut guards: Vec<syn::Ident> = vec![];
+      for attr in method.attrs.iter().filter(|attr| attr.path.is_ident("access_control")) {
+        match attr.parse_args_with(Punctuated::<syn::Ident, Comma>::parse_terminated) {
+          Ok(names) => guards.extend(names),
+          Err(err) => errors.push(err),
         }
-
-        previous = current;
-        current = extract_identifier(&g);
       }
-    },
-    _ => ()
+
+      let is_constructor = method.attrs.iter().any(|attr| attr.path.is_ident("constructor"));
+      let is_mutating = method.attrs.iter().any(|attr| attr.path.is_ident("mutates"));
+
+      exported.push(ExportAttribute {
+        fn_name: method.sig.ident.clone(),
+        binding,
+        guards,
+        sig: method.sig.clone(),
+        is_constructor,
+        is_mutating,
+        span: export_attr.span(),
+      });
+    }
   }
 
-  v
+  exported
 }
 
-fn parse_attributes(attr_string: String) -> FvmActorMacroAttribute {
-  let mut attrs = FvmActorMacroAttribute::default();
-  
+fn parse_attributes(args: &Punctuated<MetaNameValue, Comma>, errors: &mut Vec<syn::Error>) -> FvmActorMacroAttribute {
   // invoke by default
-  attrs.invoke = true;
-
-  let vec = attr_string
-    .split(",")
-    .into_iter()
-    .map(|x| x.to_string())
-    .collect::<Vec<String>>()
-    .into_iter()
-    .map(|x: String| x.replace("\"", "")
-      .split(" = ")
-      .into_iter()
-      .map(|x| x.trim().to_string())
-      .collect::<Vec<String>>())
-    .collect::<Vec<Vec<String>>>();
-  
-  for i in vec {
-    match i[0].as_str() {
-      "state" => {
-        attrs.state = i[1].to_string();
+  let mut attrs = FvmActorMacroAttribute { invoke: true, ..Default::default() };
+
+  for nv in args {
+    let key = match nv.path.get_ident() {
+      Some(ident) => ident.to_string(),
+      None => {
+        errors.push(syn::Error::new(nv.path.span(), "expected an identifier"));
+        continue;
+      }
+    };
+
+    match key.as_str() {
+      "state" => match &nv.lit {
+        Lit::Str(s) => {
+          attrs.state = s.value();
+          attrs.state_span = s.span();
+        }
+        _ => errors.push(syn::Error::new(nv.lit.span(), "state must be a string literal")),
       },
-      "dispatch" => {
-        attrs.dispatch_type = i[1].to_string();
+      "dispatch" => match &nv.lit {
+        Lit::Str(s) => attrs.dispatch_type = s.value(),
+        _ => errors.push(syn::Error::new(nv.lit.span(), "dispatch must be a string literal")),
       },
-      "invoke" => {
-        attrs.invoke = i[1].parse().unwrap_or_default();
-      }
-      _ => {}
+      "invoke" => match &nv.lit {
+        Lit::Bool(b) => attrs.invoke = b.value(),
+        _ => errors.push(syn::Error::new(nv.lit.span(), "invoke must be a boolean literal")),
+      },
+      _ => errors.push(syn::Error::new(nv.path.span(), format!("unknown attribute `{}`", key))),
     }
   }
 
-  println!("{:?}", attrs);
-
   attrs
 }
-fn parse_macro_args(attr_string: String) -> Vec<Vec<String>> {
-  attr_string
-    .split(",")
-    .into_iter()
-    .map(|x| x.to_string())
-    .collect::<Vec<String>>()
-    .into_iter()
-    .map(|x: String| x.replace("\"", "")
-      .split(" = ")
-      .into_iter()
-      .map(|x| x.trim().to_string())
-      .collect::<Vec<String>>())
-    .collect::<Vec<Vec<String>>>()
+
+fn to_compile_errors(errors: Vec<syn::Error>) -> proc_macro2::TokenStream {
+  errors.into_iter().map(|e| e.to_compile_error()).collect()
 }
 
-fn extract_binding (parsed_args: &Vec<Vec<String>>) -> Option<String> {
-  for arg in parsed_args {
-    match arg[0].as_str() {
-      "binding" => {
-        return Some(arg[1].clone());
-      },
-      _ => {
-        return None;
-      }
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn attrs(dispatch: &str) -> FvmActorMacroAttribute {
+    FvmActorMacroAttribute {
+      state: "State".to_string(),
+      state_span: Span::call_site(),
+      dispatch_type: dispatch.to_string(),
+      invoke: true,
     }
   }
-  None
-}
 
-fn build_fvm_actor_attributes(parsed_args: &Vec<Vec<String>>) -> FvmActorMacroAttribute {
-  let mut attrs = FvmActorMacroAttribute::default();
-  attrs.invoke = true;
+  /// Expand an impl block and assert the generated tokens are syntactically
+  /// valid Rust. This is the guard that catches malformed dispatch codegen such
+  /// as a dangling expression before the outer `match ret`.
+  fn expand_ok(src: &str, dispatch: &str) {
+    let item_impl: ItemImpl = syn::parse_str(src).expect("test impl should parse");
+    let exports = collect_exports(&item_impl, &mut vec![]);
+    let ts = impl_fvm_actor(attrs(dispatch), &item_impl, exports, vec![]);
+    syn::parse2::<syn::File>(ts).expect("generated actor code should be valid Rust");
+  }
 
-  for i in parsed_args {
-    match i[0].as_str() {
-      "state" => {
-        attrs.state = i[1].to_string();
-      },
-      "dispatch" => {
-        attrs.dispatch_type = i[1].to_string();
-      },
-      "invoke" => {
-        attrs.invoke = i[1].parse().unwrap_or_default();
-      },
-      _ => {}
-    }
+  #[test]
+  fn expands_plain_dispatch() {
+    expand_ok(
+      "impl Foo { #[export(binding = 2)] fn bar(state: State) -> Option<RawBytes> { None } }",
+      "method_num",
+    );
   }
 
-  attrs
-}
\ No newline at end of file
+  #[test]
+  fn expands_constructor_dispatch() {
+    expand_ok(
+      "impl Foo { #[constructor] #[export(binding = 1)] fn new() -> State { State } #[export(binding = 2)] fn bar(state: State) -> Option<RawBytes> { None } }",
+      "method_num",
+    );
+  }
+
+  #[test]
+  fn expands_mutating_and_guarded_methods() {
+    expand_ok(
+      "impl Foo { #[export(binding = 2)] #[access_control(only_owner)] #[mutates] fn bump(state: &mut State) -> Option<RawBytes> { None } }",
+      "method_num",
+    );
+  }
+
+  #[test]
+  fn expands_frc42_dispatch() {
+    expand_ok(
+      "impl Foo { #[export] fn transfer(state: State) -> Option<RawBytes> { None } }",
+      "frc42",
+    );
+  }
+
+  #[test]
+  fn frc42_method_number_escapes_reserved_range() {
+    let n = frc42_method_number("transfer");
+    assert!(n >= 1 << 24, "method number must be outside the reserved system range");
+    assert_eq!(n, frc42_method_number("transfer"), "derivation must be deterministic");
+  }
+}